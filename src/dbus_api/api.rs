@@ -2,7 +2,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::Path;
+use std::thread;
 use std::vec::Vec;
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -12,8 +17,10 @@ use dbus::Connection;
 use dbus::BusType;
 use dbus::Message;
 use dbus::NameFlag;
+use dbus::OwnedFd;
 use dbus::arg::Array;
 use dbus::arg::IterAppend;
+use dbus::arg::Variant;
 use dbus::tree::Access;
 use dbus::tree::EmitsChangedSignal;
 use dbus::tree::Factory;
@@ -26,12 +33,15 @@ use dbus::tree::Tree;
 use dbus::ConnectionItem;
 
 use engine::Engine;
+use engine::errors::{EngineError, ErrorEnum};
 use stratis::VERSION;
 
 use super::filesystem::create_dbus_filesystem;
 use super::blockdev::create_dbus_blockdev;
 use super::pool::create_dbus_pool;
-use super::types::{ActionQueue, DeferredAction, DbusContext, DbusErrorEnum, TData};
+use super::command::{Command, Interpreter, Response};
+use super::types::{ActionQueue, CompletionResult, CompletionSink, DeferredAction, DbusContext,
+                    DbusErrorEnum, PendingReply, TData};
 use super::util::STRATIS_BASE_PATH;
 use super::util::STRATIS_BASE_SERVICE;
 use super::util::engine_to_dbus_err_tuple;
@@ -49,19 +59,24 @@ fn create_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     let force: bool = get_next_arg(&mut iter, 2)?;
     let devs: Array<&str, _> = get_next_arg(&mut iter, 3)?;
 
-    let blockdevs = devs.map(|x| Path::new(x)).collect::<Vec<&Path>>();
+    let command = Command::CreatePool {
+        name: name.to_owned(),
+        devices: devs.map(|x| Path::new(x).to_owned()).collect(),
+        redundancy: tuple_to_option(redundancy),
+        force,
+    };
 
     let object_path = m.path.get_name();
     let dbus_context = m.tree.get_data();
     let mut engine = dbus_context.engine.borrow_mut();
-    let result = engine.create_pool(name, &blockdevs, tuple_to_option(redundancy), force);
+    let result = Interpreter::run(&mut engine, command);
 
     let return_message = message.method_return();
 
     let default_return: (dbus::Path, Vec<dbus::Path>) = (dbus::Path::default(), Vec::new());
 
     let msg = match result {
-        Ok(pool_uuid) => {
+        Ok(Response::PoolCreated { uuid: pool_uuid, .. }) => {
             let pool_object_path: dbus::Path =
                 create_dbus_pool(dbus_context, object_path.clone(), pool_uuid);
 
@@ -76,6 +91,7 @@ fn create_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
                                    msg_code_ok(),
                                    msg_string_ok())
         }
+        Ok(_) => unreachable!("Command::CreatePool always yields Response::PoolCreated"),
         Err(x) => {
             let (rc, rs) = engine_to_dbus_err_tuple(&x);
             return_message.append3(default_return, rc, rs)
@@ -103,14 +119,19 @@ fn destroy_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
         }
     };
 
-    let msg = match dbus_context.engine.borrow_mut().destroy_pool(pool_uuid) {
-        Ok(action) => {
+    let command = Command::DestroyPool { uuid: pool_uuid };
+    let result = Interpreter::run(&mut dbus_context.engine.borrow_mut(), command);
+
+    let msg = match result {
+        Ok(Response::PoolDestroyed { action }) => {
             dbus_context
                 .actions
                 .borrow_mut()
                 .push_remove(object_path);
+            dbus_context.unregister_pool_properties(pool_uuid);
             return_message.append3(action, msg_code_ok(), msg_string_ok())
         }
+        Ok(_) => unreachable!("Command::DestroyPool always yields Response::PoolDestroyed"),
         Err(err) => {
             let (rc, rs) = engine_to_dbus_err_tuple(&err);
             return_message.append3(default_return, rc, rs)
@@ -119,6 +140,140 @@ fn destroy_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     Ok(vec![msg])
 }
 
+fn export_pool_metadata(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let object_path: dbus::Path<'static> = get_next_arg(&mut iter, 0)?;
+    let fd: OwnedFd = get_next_arg(&mut iter, 1)?;
+
+    let dbus_context = m.tree.get_data();
+    let default_return: u64 = 0;
+    let return_message = message.method_return();
+
+    let pool_uuid = match m.tree.get(&object_path) {
+        Some(pool_path) => get_data!(pool_path; default_return; return_message).uuid,
+        None => {
+            let (rc, rs) = engine_to_dbus_err_tuple(
+                &EngineError::Engine(ErrorEnum::NotFound,
+                                     format!("no pool at {}", object_path)));
+            return Ok(vec![return_message.append3(default_return, rc, rs)]);
+        }
+    };
+
+    let command = Command::ExportPoolMetadata { uuid: pool_uuid };
+    let result = Interpreter::run(&mut dbus_context.engine.borrow_mut(), command);
+
+    match result {
+        Ok(Response::PoolMetadata { blob }) => {
+            // The blob is already in hand; only the write to `fd` can block
+            // on a slow reader, so that's the only part that moves to a
+            // worker thread. The worker never touches `dbus_context` or
+            // `return_message` -- both are `!Send` -- it only gets a
+            // `CompletionSink` (plain `Arc`/fd) and hands back a `Send`
+            // result over it; `return_message` stays parked in `pending`
+            // until the reactor resolves it on the main thread.
+            let serial = dbus_context.register_pending(PendingReply::Export(return_message));
+            spawn_fd_write(dbus_context.completion_sink(), serial, fd, blob);
+            Ok(vec![])
+        }
+        Ok(_) => unreachable!("Command::ExportPoolMetadata always yields Response::PoolMetadata"),
+        Err(err) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&err);
+            Ok(vec![return_message.append3(default_return, rc, rs)])
+        }
+    }
+}
+
+fn import_pool_metadata(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let fd: OwnedFd = get_next_arg(&mut iter, 0)?;
+    let force: bool = get_next_arg(&mut iter, 1)?;
+
+    let dbus_context = m.tree.get_data();
+    let return_message = message.method_return();
+
+    // Reading an arbitrarily large blob from `fd` can block on a slow
+    // writer; only that read happens on a worker thread. The engine call it
+    // feeds into still has to run on the main thread -- `Engine` isn't
+    // `Send` either -- so `complete_metadata_transfer` runs it once the
+    // reactor picks the blob back up.
+    let serial = dbus_context.register_pending(PendingReply::Import(return_message, force));
+    spawn_fd_read(dbus_context.completion_sink(), serial, fd);
+    Ok(vec![])
+}
+
+/// Write `blob` to `fd` on a worker thread and hand the outcome back to the
+/// reactor through `sink`, tagged with `serial`. Captures nothing but `Send`
+/// data: no `DbusContext`, no `Message`.
+fn spawn_fd_write(sink: CompletionSink, serial: u64, fd: OwnedFd, blob: Vec<u8>) {
+    let raw_fd = fd.as_raw_fd();
+    ::std::mem::forget(fd);
+    thread::spawn(move || {
+        let mut file = unsafe { File::from_raw_fd(raw_fd) };
+        let result = file.write_all(&blob)
+            .map(|_| blob.len() as u64)
+            .map_err(|e| format!("{}", e));
+        sink.push(serial, CompletionResult::Write(result));
+    });
+}
+
+/// Read all of `fd` on a worker thread and hand the blob back to the
+/// reactor through `sink`, tagged with `serial`. Captures nothing but
+/// `Send` data: no `DbusContext`, no `Message`, no engine call -- that
+/// still has to happen on the main thread, in `complete_metadata_transfer`.
+fn spawn_fd_read(sink: CompletionSink, serial: u64, fd: OwnedFd) {
+    let raw_fd = fd.as_raw_fd();
+    ::std::mem::forget(fd);
+    thread::spawn(move || {
+        let mut file = unsafe { File::from_raw_fd(raw_fd) };
+        let mut blob = Vec::new();
+        let result = file.read_to_end(&mut blob).map(|_| blob).map_err(|e| format!("{}", e));
+        sink.push(serial, CompletionResult::Read(result));
+    });
+}
+
+/// Turn a worker thread's `CompletionResult` into the `Message` reply its
+/// `PendingReply` was waiting on. Runs the engine call `ImportPoolMetadata`
+/// couldn't run off-thread, now that the blocking read is done. Called only
+/// from the reactor, on the main thread, where `Message`/`Engine` are safe
+/// to touch again.
+pub(crate) fn complete_metadata_transfer(dbus_context: &DbusContext,
+                                         reply: PendingReply,
+                                         result: CompletionResult)
+                                         -> Message {
+    match (reply, result) {
+        (PendingReply::Export(return_message), CompletionResult::Write(Ok(bytes_written))) => {
+            return_message.append3(bytes_written, msg_code_ok(), msg_string_ok())
+        }
+        (PendingReply::Export(return_message), CompletionResult::Write(Err(e))) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&EngineError::Engine(ErrorEnum::Error, e));
+            return_message.append3(0u64, rc, rs)
+        }
+        (PendingReply::Import(return_message, force), CompletionResult::Read(Ok(blob))) => {
+            let command = Command::ImportPoolMetadata { blob, force };
+            match Interpreter::run(&mut dbus_context.engine.borrow_mut(), command) {
+                Ok(_) => return_message.append3(true, msg_code_ok(), msg_string_ok()),
+                Err(err) => {
+                    let (rc, rs) = engine_to_dbus_err_tuple(&err);
+                    return_message.append3(false, rc, rs)
+                }
+            }
+        }
+        (PendingReply::Import(return_message, _), CompletionResult::Read(Err(e))) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&EngineError::Engine(ErrorEnum::Error, e));
+            return_message.append3(false, rc, rs)
+        }
+        (PendingReply::Export(_), CompletionResult::Read(_)) |
+        (PendingReply::Import(..), CompletionResult::Write(_)) => {
+            unreachable!("ExportPoolMetadata always pairs with a Write result, \
+                          ImportPoolMetadata always with a Read result")
+        }
+    }
+}
+
 fn get_version(i: &mut IterAppend, _p: &PropInfo<MTFn<TData>, TData>) -> Result<(), MethodErr> {
     i.append(VERSION);
     Ok(())
@@ -131,10 +286,8 @@ fn configure_simulator(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     let denominator: u32 = get_next_arg(&mut iter, 0)?;
 
     let dbus_context = m.tree.get_data();
-    let result = dbus_context
-        .engine
-        .borrow_mut()
-        .configure_simulator(denominator);
+    let command = Command::ConfigureSimulator { denominator };
+    let result = Interpreter::run(&mut dbus_context.engine.borrow_mut(), command);
 
     let return_message = message.method_return();
 
@@ -174,6 +327,20 @@ fn get_base_tree<'a>(dbus_context: DbusContext) -> (Tree<MTFn<TData>, TData>, db
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let export_pool_metadata_method = f.method("ExportPoolMetadata", (), export_pool_metadata)
+        .in_arg(("pool", "o"))
+        .in_arg(("fd", "h"))
+        .out_arg(("bytes_written", "t"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let import_pool_metadata_method = f.method("ImportPoolMetadata", (), import_pool_metadata)
+        .in_arg(("fd", "h"))
+        .in_arg(("force", "b"))
+        .out_arg(("result", "b"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
     let version_property = f.property::<&str, _>("Version", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::Const)
@@ -188,6 +355,8 @@ fn get_base_tree<'a>(dbus_context: DbusContext) -> (Tree<MTFn<TData>, TData>, db
                  .add_m(create_pool_method)
                  .add_m(destroy_pool_method)
                  .add_m(configure_simulator_method)
+                 .add_m(export_pool_metadata_method)
+                 .add_m(import_pool_metadata_method)
                  .add_p(version_property));
 
     let path = obj_path.get_name().to_owned();
@@ -226,10 +395,11 @@ pub fn connect(engine: Rc<RefCell<Engine>>)
     Ok((c, tree, dbus_context))
 }
 
-/// Update the dbus tree with deferred adds and removes.
-fn process_deferred_actions(c: &Connection,
-                            tree: &mut Tree<MTFn<TData>, TData>,
-                            actions: &mut ActionQueue)
+/// Update the dbus tree with deferred adds and removes, and emit any
+/// deferred `PropertiesChanged` signals.
+pub(crate) fn process_deferred_actions(c: &Connection,
+                                       tree: &mut Tree<MTFn<TData>, TData>,
+                                       actions: &mut ActionQueue)
                             -> Result<(), dbus::Error> {
     for action in actions.drain() {
         match action {
@@ -241,6 +411,28 @@ fn process_deferred_actions(c: &Connection,
                 c.unregister_object_path(&path);
                 tree.remove(&path);
             }
+            DeferredAction::PropertyChanged {
+                path,
+                interface,
+                property,
+                value,
+            } => {
+                let mut changed: HashMap<String, Variant<Box<dbus::arg::RefArg>>> = HashMap::new();
+                changed.insert(property, value);
+                let invalidated: Vec<String> = Vec::new();
+                let signal = Message::new_signal(&path,
+                                                 "org.freedesktop.DBus.Properties",
+                                                 "PropertiesChanged")
+                        .map_err(|e| {
+                                     dbus::Error::new_custom("org.storage.stratis1.Signal", &e)
+                                 })?
+                        .append3(interface, changed, invalidated);
+                c.send(signal)
+                    .map_err(|_| {
+                                 dbus::Error::new_custom("org.storage.stratis1.Signal",
+                                                         "failed to send PropertiesChanged")
+                             })?;
+            }
         }
     }
     Ok(())
@@ -0,0 +1,90 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Builds the D-Bus object for a single block device, including its
+//! mutable, cached `State` property.
+
+use std::rc::Rc;
+
+use dbus;
+use dbus::arg::IterAppend;
+use dbus::tree::{Access, EmitsChangedSignal, Factory, MTFn, MethodErr, PropInfo};
+
+use engine::{BlockDev, DevUuid, Engine};
+
+use super::types::{DbusContext, OPath, PropertyCache, TData};
+use super::util::STRATIS_BASE_SERVICE;
+
+/// Blockdevs are reached only through their owning pool, so finding one by
+/// uuid means checking every pool's blockdev list.
+fn find_blockdev(engine: &Engine, uuid: DevUuid) -> Option<&BlockDev> {
+    engine
+        .pools()
+        .into_iter()
+        .flat_map(|pool| pool.blockdevs())
+        .find(|bd| bd.uuid() == uuid)
+}
+
+fn blockdev_interface_name() -> String {
+    format!("{}.{}", STRATIS_BASE_SERVICE, "BlockDev")
+}
+
+/// Bundles the one cache `BlockDev` has today; lets `DbusContext` hold onto
+/// it so it can be refreshed from outside `on_get` -- see `refresh`.
+pub struct BlockDevProperties {
+    state: Rc<PropertyCache<String>>,
+}
+
+impl BlockDevProperties {
+    /// Re-read `uuid`'s blockdev from the engine and update the cached
+    /// `State` property, queuing `PropertiesChanged` if it moved. Called
+    /// whenever the engine's state might have changed -- in particular, from
+    /// the udev handler right after it tells the engine about a hotplug
+    /// event -- instead of waiting for a client to read the property.
+    pub fn refresh(&self, dbus_context: &DbusContext, path: dbus::Path<'static>, uuid: DevUuid) {
+        if let Some(bd) = find_blockdev(&*dbus_context.engine.borrow(), uuid) {
+            self.state
+                .set(&dbus_context.actions, path, &blockdev_interface_name(), "State", bd.state());
+        }
+    }
+}
+
+fn get_state(i: &mut IterAppend,
+             _p: &PropInfo<MTFn<TData>, TData>,
+             cache: &Rc<PropertyCache<String>>)
+             -> Result<(), MethodErr> {
+    i.append(&cache.get());
+    Ok(())
+}
+
+/// Register a D-Bus object for the blockdev `uuid` under `parent`, with its
+/// `State` property backed by a cache that's refreshed (and diffed, to
+/// decide whether to emit `PropertiesChanged`) when the object is created
+/// and whenever the udev handler matches this blockdev. Reading the
+/// property returns the cached value and does not refresh it.
+pub fn create_dbus_blockdev(dbus_context: &DbusContext,
+                             parent: dbus::Path<'static>,
+                             uuid: DevUuid)
+                             -> dbus::Path<'static> {
+    let f = Factory::new_fn();
+
+    let properties = Rc::new(BlockDevProperties { state: Rc::new(PropertyCache::new(String::new())) });
+
+    let state_cache = Rc::clone(&properties.state);
+    let state_property = f.property::<&str, _>("State", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::True)
+        .on_get(move |i, p| get_state(i, p, &state_cache));
+
+    let object_name = format!("{}/{}", parent, uuid.simple());
+    let object_path = f.object_path(object_name, (OPath { uuid },))
+        .introspectable()
+        .add(f.interface(blockdev_interface_name(), ()).add_p(state_property));
+
+    let path = object_path.get_name().to_owned();
+    dbus_context.register_blockdev_properties(uuid, Rc::clone(&properties));
+    dbus_context.actions.borrow_mut().push_add(object_path);
+    properties.refresh(dbus_context, path.clone(), uuid);
+    path
+}
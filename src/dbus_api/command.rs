@@ -0,0 +1,99 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A transport-agnostic command layer. D-Bus method handlers and any other
+//! `Gateway` (see `gateway.rs`) translate their wire format into a
+//! `Command`, hand it to an `Interpreter`, and translate the resulting
+//! `Response` back. This keeps engine invocation in one place instead of
+//! duplicated per transport.
+
+use std::path::{Path, PathBuf};
+
+use engine::{Engine, PoolUuid};
+use engine::errors::{EngineError, EngineResult, ErrorEnum};
+
+/// A single engine operation, independent of how it was requested.
+#[derive(Debug, Clone)]
+pub enum Command {
+    CreatePool {
+        name: String,
+        devices: Vec<PathBuf>,
+        redundancy: Option<u16>,
+        force: bool,
+    },
+    DestroyPool { uuid: PoolUuid },
+    ConfigureSimulator { denominator: u32 },
+    /// Serialize `uuid`'s on-disk metadata/MDA regions. The caller is
+    /// responsible for streaming the bytes to wherever they're headed; this
+    /// layer knows nothing about fds.
+    ExportPoolMetadata { uuid: PoolUuid },
+    /// Reconstruct a pool record from a previously exported metadata blob,
+    /// overwriting any existing record for that pool's uuid if `force` is
+    /// set.
+    ImportPoolMetadata { blob: Vec<u8>, force: bool },
+}
+
+/// The result of running a `Command`, still transport-agnostic.
+#[derive(Debug, Clone)]
+pub enum Response {
+    PoolCreated {
+        uuid: PoolUuid,
+        devices: Vec<PathBuf>,
+    },
+    PoolDestroyed { action: bool },
+    /// The serialized metadata/MDA blob produced by `ExportPoolMetadata`.
+    PoolMetadata { blob: Vec<u8> },
+    Done,
+}
+
+/// Executes `Command`s against an `Engine`. Holds no state of its own; it
+/// exists so that every transport calls engine methods the same way.
+pub struct Interpreter;
+
+impl Interpreter {
+    /// Run `command` against `engine`, returning a transport-agnostic
+    /// `Response` on success or the `EngineError` the operation failed with.
+    pub fn run(engine: &mut Engine, command: Command) -> EngineResult<Response> {
+        match command {
+            Command::CreatePool {
+                name,
+                devices,
+                redundancy,
+                force,
+            } => {
+                let paths = devices.iter().map(|p| p.as_path()).collect::<Vec<&Path>>();
+                let uuid = engine.create_pool(&name, &paths, redundancy, force)?;
+                let devices = {
+                    let pool = engine
+                        .get_pool(uuid)
+                        .ok_or_else(|| {
+                            EngineError::Engine(ErrorEnum::NotFound,
+                                                 "pool vanished immediately after creation".into())
+                        })?;
+                    pool.blockdevs()
+                        .iter()
+                        .map(|bd| bd.devnode().to_owned())
+                        .collect()
+                };
+                Ok(Response::PoolCreated { uuid, devices })
+            }
+            Command::DestroyPool { uuid } => {
+                let action = engine.destroy_pool(uuid)?;
+                Ok(Response::PoolDestroyed { action })
+            }
+            Command::ConfigureSimulator { denominator } => {
+                engine.configure_simulator(denominator)?;
+                Ok(Response::Done)
+            }
+            Command::ExportPoolMetadata { uuid } => {
+                let blob = engine.export_pool_metadata(uuid)?;
+                Ok(Response::PoolMetadata { blob })
+            }
+            Command::ImportPoolMetadata { blob, force } => {
+                engine.import_pool_metadata(&blob, force)?;
+                Ok(Response::Done)
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Builds the D-Bus object for a single filesystem, including its mutable,
+//! cached `Used` property.
+
+use std::rc::Rc;
+
+use dbus;
+use dbus::arg::IterAppend;
+use dbus::tree::{Access, EmitsChangedSignal, Factory, MTFn, MethodErr, PropInfo};
+
+use engine::{Engine, Filesystem, FilesystemUuid};
+
+use super::types::{DbusContext, OPath, PropertyCache, TData};
+use super::util::STRATIS_BASE_SERVICE;
+
+/// Filesystems are reached only through their owning pool, so finding one
+/// by uuid means checking every pool's filesystem list.
+fn find_filesystem(engine: &Engine, uuid: FilesystemUuid) -> Option<&Filesystem> {
+    engine
+        .pools()
+        .into_iter()
+        .flat_map(|pool| pool.filesystems())
+        .find(|fs| fs.uuid() == uuid)
+}
+
+fn filesystem_interface_name() -> String {
+    format!("{}.{}", STRATIS_BASE_SERVICE, "Filesystem")
+}
+
+/// Bundles the one cache `Filesystem` has today; lets `DbusContext` hold
+/// onto it so it can be refreshed from outside `on_get` -- see `refresh`.
+pub struct FilesystemProperties {
+    used: Rc<PropertyCache<u64>>,
+}
+
+impl FilesystemProperties {
+    /// Re-read `uuid`'s filesystem from the engine and update the cached
+    /// `Used` property, queuing `PropertiesChanged` if it moved. Only
+    /// `create_dbus_filesystem` calls this, once, when the object is
+    /// registered.
+    pub fn refresh(&self,
+                    dbus_context: &DbusContext,
+                    path: dbus::Path<'static>,
+                    uuid: FilesystemUuid) {
+        if let Some(fs) = find_filesystem(&*dbus_context.engine.borrow(), uuid) {
+            self.used
+                .set(&dbus_context.actions, path, &filesystem_interface_name(), "Used", fs.used());
+        }
+    }
+}
+
+fn get_used(i: &mut IterAppend,
+            _p: &PropInfo<MTFn<TData>, TData>,
+            cache: &Rc<PropertyCache<u64>>)
+            -> Result<(), MethodErr> {
+    i.append(cache.get());
+    Ok(())
+}
+
+/// Register a D-Bus object for the filesystem `uuid` under `parent`, with
+/// its `Used` property backed by a cache that's filled once, here. Nothing
+/// refreshes the cache afterwards and reading the property does not either,
+/// so `Used` keeps the value read at registration and no
+/// `PropertiesChanged` is emitted for it.
+pub fn create_dbus_filesystem(dbus_context: &DbusContext,
+                               parent: dbus::Path<'static>,
+                               uuid: FilesystemUuid)
+                               -> dbus::Path<'static> {
+    let f = Factory::new_fn();
+
+    let properties = Rc::new(FilesystemProperties { used: Rc::new(PropertyCache::new(0)) });
+
+    let used_cache = Rc::clone(&properties.used);
+    let used_property = f.property::<u64, _>("Used", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::True)
+        .on_get(move |i, p| get_used(i, p, &used_cache));
+
+    let object_name = format!("{}/{}", parent, uuid.simple());
+    let object_path = f.object_path(object_name, (OPath { uuid },))
+        .introspectable()
+        .add(f.interface(filesystem_interface_name(), ()).add_p(used_property));
+
+    let path = object_path.get_name().to_owned();
+    dbus_context.register_filesystem_properties(uuid, Rc::clone(&properties));
+    dbus_context.actions.borrow_mut().push_add(object_path);
+    properties.refresh(dbus_context, path.clone(), uuid);
+    path
+}
@@ -0,0 +1,299 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A second, much simpler `Gateway` onto the engine: a line-oriented JSON
+//! request/response server on a Unix domain socket. Lets scripts and tests
+//! drive stratisd without a D-Bus bus, and keeps protocol encoding out of
+//! the engine entirely -- it goes through the same `Command`/`Interpreter`
+//! pair the D-Bus handlers in `api.rs` use.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use engine::Engine;
+use engine::errors::EngineError;
+
+use super::command::{Command, Interpreter, Response};
+
+/// A request as read off the wire, before it is turned into a `Command`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command")]
+enum WireRequest {
+    CreatePool {
+        name: String,
+        devices: Vec<PathBuf>,
+        redundancy: Option<u16>,
+        #[serde(default)]
+        force: bool,
+    },
+    DestroyPool { uuid: String },
+    ConfigureSimulator { denominator: u32 },
+}
+
+/// A response as written to the wire.
+#[derive(Debug, Serialize)]
+struct WireResponse {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A transport that can be driven by the reactor alongside the D-Bus
+/// connection. `Gateway` is deliberately minimal, and deliberately
+/// non-blocking: the reactor only ever calls in when a fd it registered is
+/// readable, and every call must return promptly so one slow or silent
+/// client can't stall the whole reactor.
+pub trait Gateway {
+    /// The fd the reactor should register for readability.
+    fn listen_fd(&self) -> RawFd;
+
+    /// Accept one pending connection, returning its fd so the reactor can
+    /// register it for readability alongside `listen_fd`. `None` means
+    /// nothing was accepted (e.g. the accept queue was already empty).
+    fn accept_one(&self, engine: &Rc<RefCell<Engine>>) -> Option<RawFd>;
+
+    /// Service one readability event on a connection previously returned by
+    /// `accept_one`: read whatever is available without blocking, act on
+    /// any complete requests it makes up, and report whether the
+    /// connection is still open. A `false` return tells the reactor to
+    /// deregister `fd` and drop it.
+    fn service(&self, fd: RawFd, engine: &Rc<RefCell<Engine>>) -> bool;
+}
+
+/// A `Gateway` that accepts newline-delimited JSON requests on a Unix
+/// socket and writes back a newline-delimited JSON `WireResponse` per
+/// request. Every connection is non-blocking and serviced incrementally as
+/// the reactor reports it readable, so a client that connects and then
+/// goes quiet mid-request never holds up D-Bus traffic or other clients.
+pub struct UnixSocketGateway {
+    listener: UnixListener,
+    // Keyed by fd, which is also what `accept_one` hands the reactor and
+    // what `service` gets called back with.
+    connections: RefCell<HashMap<RawFd, Connection>>,
+}
+
+/// A client connection's socket plus whatever partial line hasn't been
+/// terminated by a `\n` yet.
+struct Connection {
+    stream: UnixStream,
+    pending: Vec<u8>,
+}
+
+impl UnixSocketGateway {
+    /// Bind a new gateway at `socket_path`, replacing any stale socket file
+    /// left behind by a previous run.
+    pub fn new(socket_path: &Path) -> io::Result<UnixSocketGateway> {
+        let _ = ::std::fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path)?;
+        listener.set_nonblocking(true)?;
+        Ok(UnixSocketGateway {
+               listener,
+               connections: RefCell::new(HashMap::new()),
+           })
+    }
+
+    /// Pull every complete, newline-terminated line out of `conn.pending`,
+    /// run it, and write back a response, without touching the socket.
+    /// Leaves a trailing partial line (if any) in `conn.pending` for the
+    /// next read to complete.
+    fn drain_requests(conn: &mut Connection, engine: &Rc<RefCell<Engine>>) {
+        while let Some(newline) = conn.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = conn.pending.drain(..=newline).collect();
+            let line = &line[..line.len() - 1];
+            if line.is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_slice::<WireRequest>(line) {
+                Ok(request) => run_request(engine, request),
+                Err(e) => {
+                    WireResponse {
+                        ok: false,
+                        result: None,
+                        error: Some(format!("malformed request: {}", e)),
+                    }
+                }
+            };
+            if let Ok(mut text) = serde_json::to_string(&response) {
+                text.push('\n');
+                let _ = conn.stream.write_all(text.as_bytes());
+            }
+        }
+    }
+}
+
+impl Gateway for UnixSocketGateway {
+    fn listen_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    fn accept_one(&self, _engine: &Rc<RefCell<Engine>>) -> Option<RawFd> {
+        let (stream, _addr) = self.listener.accept().ok()?;
+        stream.set_nonblocking(true).ok()?;
+        let fd = stream.as_raw_fd();
+        self.connections
+            .borrow_mut()
+            .insert(fd,
+                     Connection {
+                         stream,
+                         pending: Vec::new(),
+                     });
+        Some(fd)
+    }
+
+    fn service(&self, fd: RawFd, engine: &Rc<RefCell<Engine>>) -> bool {
+        let mut connections = self.connections.borrow_mut();
+        let keep_open = {
+            let conn = match connections.get_mut(&fd) {
+                Some(conn) => conn,
+                None => return false,
+            };
+
+            let mut buf = [0u8; 4096];
+            let closed = loop {
+                match conn.stream.read(&mut buf) {
+                    Ok(0) => break true,
+                    Ok(n) => conn.pending.extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+                    Err(_) => break true,
+                }
+            };
+            Self::drain_requests(conn, engine);
+            !closed
+        };
+        if !keep_open {
+            connections.remove(&fd);
+        }
+        keep_open
+    }
+}
+
+/// Parse a `WireRequest` into a `Command`, run it through the `Interpreter`,
+/// and translate the `Response`/`EngineError` back into a `WireResponse`.
+fn run_request(engine: &Rc<RefCell<Engine>>, request: WireRequest) -> WireResponse {
+    let command = match request {
+        WireRequest::CreatePool {
+            name,
+            devices,
+            redundancy,
+            force,
+        } => {
+            Command::CreatePool {
+                name,
+                devices,
+                redundancy,
+                force,
+            }
+        }
+        WireRequest::DestroyPool { uuid } => {
+            match uuid.parse() {
+                Ok(uuid) => Command::DestroyPool { uuid },
+                Err(_) => {
+                    return WireResponse {
+                               ok: false,
+                               result: None,
+                               error: Some(format!("{} is not a valid pool uuid", uuid)),
+                           }
+                }
+            }
+        }
+        WireRequest::ConfigureSimulator { denominator } => {
+            Command::ConfigureSimulator { denominator }
+        }
+    };
+
+    let mut engine = engine.borrow_mut();
+    match Interpreter::run(&mut engine, command) {
+        Ok(response) => {
+            WireResponse {
+                ok: true,
+                result: Some(response_to_json(response)),
+                error: None,
+            }
+        }
+        Err(err) => {
+            WireResponse {
+                ok: false,
+                result: None,
+                error: Some(engine_error_to_string(&err)),
+            }
+        }
+    }
+}
+
+fn response_to_json(response: Response) -> serde_json::Value {
+    match response {
+        Response::PoolCreated { uuid, devices } => {
+            json!({
+                "uuid": uuid.simple().to_string(),
+                "devices": devices,
+            })
+        }
+        Response::PoolDestroyed { action } => json!({ "action": action }),
+        Response::PoolMetadata { blob } => json!({ "blob": blob }),
+        Response::Done => json!({}),
+    }
+}
+
+fn engine_error_to_string(err: &EngineError) -> String {
+    format!("{}", err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_create_pool_request() {
+        let request: WireRequest =
+            serde_json::from_str(r#"{"command": "CreatePool", "name": "p1", "devices": ["/dev/sda"], "redundancy": null}"#).unwrap();
+        match request {
+            WireRequest::CreatePool {
+                name,
+                devices,
+                redundancy,
+                force,
+            } => {
+                assert_eq!(name, "p1");
+                assert_eq!(devices, vec![PathBuf::from("/dev/sda")]);
+                assert_eq!(redundancy, None);
+                assert!(!force);
+            }
+            _ => panic!("expected CreatePool"),
+        }
+    }
+
+    #[test]
+    fn parses_destroy_pool_request() {
+        let request: WireRequest =
+            serde_json::from_str(r#"{"command": "DestroyPool", "uuid": "some-uuid"}"#).unwrap();
+        match request {
+            WireRequest::DestroyPool { uuid } => assert_eq!(uuid, "some-uuid"),
+            _ => panic!("expected DestroyPool"),
+        }
+    }
+
+    #[test]
+    fn malformed_request_is_rejected_without_connecting() {
+        let result = serde_json::from_str::<WireRequest>("not json");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn response_omits_absent_result_and_error() {
+        let response = WireResponse {
+            ok: true,
+            result: None,
+            error: None,
+        };
+        let text = serde_json::to_string(&response).unwrap();
+        assert_eq!(text, r#"{"ok":true}"#);
+    }
+}
@@ -0,0 +1,20 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The D-Bus API surface, plus the reactor and transport-agnostic
+//! command layer it shares with the Unix-socket gateway.
+
+#[macro_use]
+mod util;
+
+pub mod api;
+pub mod blockdev;
+pub mod command;
+pub mod filesystem;
+pub mod gateway;
+pub mod pool;
+pub mod reactor;
+pub mod types;
+
+pub use self::api::connect;
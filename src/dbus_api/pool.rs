@@ -0,0 +1,114 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Builds the D-Bus object for a single pool, including the mutable,
+//! cached properties that notify subscribers when the engine's view of the
+//! pool's space usage moves.
+
+use std::rc::Rc;
+
+use dbus;
+use dbus::arg::IterAppend;
+use dbus::tree::{Access, EmitsChangedSignal, Factory, MTFn, MethodErr, PropInfo};
+
+use engine::PoolUuid;
+
+use super::types::{DbusContext, OPath, PropertyCache, TData};
+use super::util::STRATIS_BASE_SERVICE;
+
+/// Every property on `Pool` needs its own cache instance; bundled together
+/// so `create_dbus_pool` only has to thread one value through the closures
+/// it builds, and so `DbusContext` can hold onto them afterwards and
+/// refresh them from outside `on_get` -- see `refresh`.
+pub struct PoolProperties {
+    total_physical_size: Rc<PropertyCache<u64>>,
+    total_physical_used: Rc<PropertyCache<u64>>,
+}
+
+impl PoolProperties {
+    /// Re-read `uuid`'s pool from the engine and update every cached
+    /// property, queuing `PropertiesChanged` for any that actually moved.
+    /// Called whenever the engine's state might have changed -- pool
+    /// creation, udev handling -- instead of waiting for a client to read
+    /// the property, so a subscriber that never polls still sees updates.
+    pub fn refresh(&self, dbus_context: &DbusContext, path: dbus::Path<'static>, uuid: PoolUuid) {
+        if let Some(pool) = dbus_context.engine.borrow().get_pool(uuid) {
+            self.total_physical_size
+                .set(&dbus_context.actions,
+                     path.clone(),
+                     &pool_interface_name(),
+                     "TotalPhysicalSize",
+                     pool.total_physical_size());
+            self.total_physical_used
+                .set(&dbus_context.actions,
+                     path,
+                     &pool_interface_name(),
+                     "TotalPhysicalUsed",
+                     pool.total_physical_used());
+        }
+    }
+}
+
+fn get_total_physical_size(i: &mut IterAppend,
+                            _p: &PropInfo<MTFn<TData>, TData>,
+                            cache: &Rc<PropertyCache<u64>>)
+                            -> Result<(), MethodErr> {
+    i.append(cache.get());
+    Ok(())
+}
+
+fn get_total_physical_used(i: &mut IterAppend,
+                            _p: &PropInfo<MTFn<TData>, TData>,
+                            cache: &Rc<PropertyCache<u64>>)
+                            -> Result<(), MethodErr> {
+    i.append(cache.get());
+    Ok(())
+}
+
+fn pool_interface_name() -> String {
+    format!("{}.{}", STRATIS_BASE_SERVICE, "Pool")
+}
+
+/// Register a D-Bus object for the pool `uuid` under `parent`, with its
+/// `TotalPhysicalSize`/`TotalPhysicalUsed` properties backed by a cache
+/// that's refreshed (and diffed, to decide whether to emit
+/// `PropertiesChanged`) when the object is created and whenever the udev
+/// handler matches one of the pool's blockdevs. Reading the property
+/// returns the cached value and does not refresh it.
+pub fn create_dbus_pool(dbus_context: &DbusContext,
+                         parent: dbus::Path<'static>,
+                         uuid: PoolUuid)
+                         -> dbus::Path<'static> {
+    let f = Factory::new_fn();
+
+    let properties = Rc::new(PoolProperties {
+        total_physical_size: Rc::new(PropertyCache::new(0)),
+        total_physical_used: Rc::new(PropertyCache::new(0)),
+    });
+
+    let size_cache = Rc::clone(&properties.total_physical_size);
+    let total_physical_size_property = f.property::<u64, _>("TotalPhysicalSize", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::True)
+        .on_get(move |i, p| get_total_physical_size(i, p, &size_cache));
+
+    let used_cache = Rc::clone(&properties.total_physical_used);
+    let total_physical_used_property = f.property::<u64, _>("TotalPhysicalUsed", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::True)
+        .on_get(move |i, p| get_total_physical_used(i, p, &used_cache));
+
+    let object_name = format!("{}/{}", parent, uuid.simple());
+    let object_path = f.object_path(object_name, (OPath { uuid },))
+        .introspectable()
+        .add(f.interface(pool_interface_name(), ())
+                 .add_p(total_physical_size_property)
+                 .add_p(total_physical_used_property));
+
+    let path = object_path.get_name().to_owned();
+    dbus_context.register_pool_properties(uuid, Rc::clone(&properties));
+    dbus_context.actions.borrow_mut().push_add(object_path);
+    properties.refresh(dbus_context, path.clone(), uuid);
+    path
+}
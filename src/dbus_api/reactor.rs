@@ -0,0 +1,326 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A single-threaded reactor that multiplexes the D-Bus connection with a
+//! udev hotplug monitor and a signalfd, so the daemon notices device
+//! topology changes instead of only ever reacting to client requests.
+
+extern crate libudev;
+extern crate mio;
+extern crate nix;
+
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use self::mio::unix::EventedFd;
+use self::mio::{Events, Poll, PollOpt, Ready, Token as MioToken};
+use self::nix::Error as NixError;
+use self::nix::errno::Errno;
+use self::nix::sys::signal::{SigSet, SIGINT, SIGTERM};
+use self::nix::sys::signalfd::SignalFd;
+use self::nix::unistd::read;
+
+use dbus;
+use dbus::tree::{MTFn, Tree};
+use dbus::{Connection, ConnectionItem, Watch};
+
+use engine::strat_engine::util::block_monitor;
+
+use super::api::{complete_metadata_transfer, handle, process_deferred_actions};
+use super::gateway::Gateway;
+use super::types::{DbusContext, TData};
+use super::util::{child_object_path, pool_object_path};
+
+/// Identifies which registered event source woke the reactor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Token {
+    /// A watch fd belonging to the D-Bus connection.
+    DBus(RawFd),
+    /// The udev hotplug monitor socket, filtered on the "block" subsystem.
+    Udev,
+    /// The signalfd used to catch SIGINT/SIGTERM.
+    Signal,
+    /// The listening fd of a registered `Gateway`, identified by its index
+    /// in `PollContext`'s gateway list.
+    Gateway(usize),
+    /// A connection previously accepted from `Gateway(usize)`'s listening
+    /// fd, identified by that same index and the connection's own fd.
+    GatewayConn(usize, RawFd),
+    /// The read end of `DbusContext`'s completion-wakeup pipe: a worker
+    /// thread finished a deferred method reply and queued it.
+    Completion,
+}
+
+/// Maps the plain `usize` tokens mio hands back in `Events` to the logical
+/// `Token` that was registered for them.
+struct TokenMap {
+    tokens: HashMap<usize, Token>,
+    next: usize,
+}
+
+impl TokenMap {
+    fn new() -> TokenMap {
+        TokenMap {
+            tokens: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    fn insert(&mut self, token: Token) -> MioToken {
+        let id = self.next;
+        self.next += 1;
+        self.tokens.insert(id, token);
+        MioToken(id)
+    }
+
+    fn get(&self, mio_token: MioToken) -> Option<Token> {
+        self.tokens.get(&mio_token.0).cloned()
+    }
+
+    fn remove(&mut self, mio_token: MioToken) {
+        self.tokens.remove(&mio_token.0);
+    }
+}
+
+/// Drives the daemon's main loop. Replaces blocking on `Connection`
+/// `ConnectionItem`s one at a time with a single epoll instance that also
+/// watches a udev hotplug monitor and a signalfd, so several sources can be
+/// registered and waited on at once.
+pub struct PollContext {
+    poll: Poll,
+    tokens: TokenMap,
+    // Kept alive for as long as udev_monitor borrows from it.
+    _udev_context: self::libudev::Context,
+    udev_monitor: self::libudev::MonitorSocket,
+    signal_fd: SignalFd,
+    gateways: Vec<Box<Gateway>>,
+    // The mio token each currently-open gateway connection was registered
+    // under, so it can be deregistered by fd once `Gateway::service` says
+    // the connection is done.
+    gateway_conns: HashMap<RawFd, MioToken>,
+}
+
+impl PollContext {
+    /// Build a reactor watching `c`'s current watch fds, a udev monitor on
+    /// the "block" subsystem, a signalfd for SIGINT/SIGTERM, `dbus_context`'s
+    /// completion-wakeup pipe, and every `Gateway` in `gateways` (e.g. the
+    /// Unix-socket gateway), all on the same epoll instance.
+    pub fn new(c: &Connection,
+               dbus_context: &DbusContext,
+               gateways: Vec<Box<Gateway>>)
+               -> Result<PollContext, dbus::Error> {
+        let poll = Poll::new().map_err(reactor_err)?;
+        let mut tokens = TokenMap::new();
+
+        for w in c.watch_fds() {
+            register_dbus_watch(&poll, &mut tokens, &w)?;
+        }
+
+        poll.register(&EventedFd(&dbus_context.completion_read),
+                      tokens.insert(Token::Completion),
+                      Ready::readable(),
+                      PollOpt::edge())
+            .map_err(reactor_err)?;
+
+        for (i, gateway) in gateways.iter().enumerate() {
+            poll.register(&EventedFd(&gateway.listen_fd()),
+                          tokens.insert(Token::Gateway(i)),
+                          Ready::readable(),
+                          PollOpt::edge())
+                .map_err(reactor_err)?;
+        }
+
+        let udev_context = self::libudev::Context::new().map_err(reactor_err)?;
+        let udev_monitor = block_monitor(&udev_context).map_err(reactor_err)?;
+        poll.register(&EventedFd(&udev_monitor.as_raw_fd()),
+                       tokens.insert(Token::Udev),
+                       Ready::readable(),
+                       PollOpt::edge())
+            .map_err(reactor_err)?;
+
+        let mut mask = SigSet::empty();
+        mask.add(SIGINT);
+        mask.add(SIGTERM);
+        mask.thread_block().map_err(reactor_err)?;
+        let signal_fd = SignalFd::new(&mask).map_err(reactor_err)?;
+        poll.register(&EventedFd(&signal_fd.as_raw_fd()),
+                       tokens.insert(Token::Signal),
+                       Ready::readable(),
+                       PollOpt::edge())
+            .map_err(reactor_err)?;
+
+        Ok(PollContext {
+               poll,
+               tokens,
+               _udev_context: udev_context,
+               udev_monitor,
+               signal_fd,
+               gateways,
+               gateway_conns: HashMap::new(),
+           })
+    }
+
+    /// Block until a registered source becomes readable, returning the
+    /// `Token`s that fired.
+    fn wait(&self) -> Result<Vec<Token>, dbus::Error> {
+        let mut events = Events::with_capacity(16);
+        self.poll.poll(&mut events, None).map_err(reactor_err)?;
+        Ok(events
+               .iter()
+               .filter_map(|e| self.tokens.get(e.token()))
+               .collect())
+    }
+
+    /// Run the reactor until a termination signal is received, dispatching
+    /// D-Bus method calls through the existing `handle()` and forwarding
+    /// udev events to the engine.
+    pub fn run(&mut self,
+               c: &Connection,
+               tree: &mut Tree<MTFn<TData>, TData>,
+               dbus_context: &DbusContext)
+               -> Result<(), dbus::Error> {
+        'reactor: loop {
+            for token in self.wait()? {
+                match token {
+                    Token::DBus(_) => {
+                        for item in c.iter(0) {
+                            if let ConnectionItem::Nothing = item {
+                                break;
+                            }
+                            handle(c, &item, tree, dbus_context)?;
+                        }
+                    }
+                    Token::Udev => {
+                        self.handle_udev_event(dbus_context);
+                        process_deferred_actions(c, tree, &mut dbus_context.actions.borrow_mut())?;
+                    }
+                    Token::Gateway(i) => {
+                        if let Some(fd) = self.gateways
+                               .get(i)
+                               .and_then(|gateway| gateway.accept_one(&dbus_context.engine)) {
+                            let mio_token = self.tokens.insert(Token::GatewayConn(i, fd));
+                            if self.poll
+                                   .register(&EventedFd(&fd), mio_token, Ready::readable(), PollOpt::edge())
+                                   .is_ok() {
+                                self.gateway_conns.insert(fd, mio_token);
+                            } else {
+                                self.tokens.remove(mio_token);
+                            }
+                        }
+                        process_deferred_actions(c, tree, &mut dbus_context.actions.borrow_mut())?;
+                    }
+                    Token::GatewayConn(i, fd) => {
+                        let keep_open = self.gateways
+                            .get(i)
+                            .map_or(false, |gateway| gateway.service(fd, &dbus_context.engine));
+                        if !keep_open {
+                            let _ = self.poll.deregister(&EventedFd(&fd));
+                            if let Some(mio_token) = self.gateway_conns.remove(&fd) {
+                                self.tokens.remove(mio_token);
+                            }
+                        }
+                        process_deferred_actions(c, tree, &mut dbus_context.actions.borrow_mut())?;
+                    }
+                    Token::Completion => {
+                        // Drain the wakeup byte(s) so the edge-triggered
+                        // registration re-arms; the completions themselves
+                        // live in `dbus_context`, not in this pipe. The read
+                        // end is non-blocking (see `DbusContext::new`), so
+                        // this stops on `EWOULDBLOCK` instead of risking a
+                        // blocking `read()` on an empty pipe when the queued
+                        // bytes happen to fill `buf` exactly.
+                        let mut buf = [0u8; 256];
+                        loop {
+                            match read(dbus_context.completion_read, &mut buf) {
+                                Ok(0) => break,
+                                Ok(_) => continue,
+                                Err(NixError::Sys(Errno::EWOULDBLOCK)) => break,
+                                Err(_) => break,
+                            }
+                        }
+                        for (reply, result) in dbus_context.drain_completions() {
+                            let msg = complete_metadata_transfer(dbus_context, reply, result);
+                            let _ = c.send(msg);
+                        }
+                    }
+                    Token::Signal => {
+                        if self.signal_fd.read_signal().map_err(reactor_err)?.is_some() {
+                            break 'reactor;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drain pending udev events and, for any device whose `ID_WWN` or
+    /// devnode matches a blockdev belonging to one of the engine's pools,
+    /// let the engine know it appeared, was pulled, or changed, through
+    /// `Pool::block_evaluate`. No D-Bus object is added or removed here:
+    /// `block_evaluate` has no access to the `ActionQueue`, so the only
+    /// deferred actions this produces are property changes. It
+    /// refreshes the matched pool's and blockdev's cached properties right
+    /// away, since this is a point where the engine's state genuinely just
+    /// changed -- a client that only subscribed to `PropertiesChanged` and
+    /// never reads a property would otherwise never hear about it.
+    fn handle_udev_event(&self, dbus_context: &DbusContext) {
+        while let Some(event) = self.udev_monitor.receive_event() {
+            let device = event.device();
+            let devnode = match device.devnode() {
+                Some(d) => d,
+                None => continue,
+            };
+            let wwn = device.property_value("ID_WWN").and_then(|v| v.to_str());
+            let event_type = format!("{:?}", event.event_type()).to_lowercase();
+
+            let engine = dbus_context.engine.borrow();
+            for pool in engine.pools() {
+                let matched_uuid = pool
+                    .blockdevs()
+                    .iter()
+                    .find(|bd| bd.devnode() == devnode || wwn.map_or(false, |w| bd.wwn() == Some(w)))
+                    .map(|bd| bd.uuid());
+
+                let bd_uuid = match matched_uuid {
+                    Some(uuid) => uuid,
+                    None => continue,
+                };
+
+                pool.block_evaluate(&event_type, devnode, wwn);
+
+                let pool_path = pool_object_path(pool.uuid());
+                if let Some(properties) = dbus_context.pool_properties(pool.uuid()) {
+                    properties.refresh(dbus_context, pool_path.clone(), pool.uuid());
+                }
+                if let Some(properties) = dbus_context.blockdev_properties(bd_uuid) {
+                    properties.refresh(dbus_context, child_object_path(&pool_path, bd_uuid), bd_uuid);
+                }
+            }
+        }
+    }
+}
+
+/// Register a single D-Bus `Watch`'s fd with the poll instance under a
+/// `Token::DBus` entry, translating the watch's read/write flags into mio's
+/// `Ready`.
+fn register_dbus_watch(poll: &Poll, tokens: &mut TokenMap, w: &Watch) -> Result<(), dbus::Error> {
+    let fd = w.fd();
+    let mut ready = Ready::empty();
+    if w.readable() {
+        ready |= Ready::readable();
+    }
+    if w.writable() {
+        ready |= Ready::writable();
+    }
+    poll.register(&EventedFd(&fd),
+                  tokens.insert(Token::DBus(fd)),
+                  ready,
+                  PollOpt::edge())
+        .map_err(reactor_err)
+}
+
+fn reactor_err<E: ::std::fmt::Display>(e: E) -> dbus::Error {
+    dbus::Error::new_custom("org.storage.stratis1.Reactor", &format!("{}", e))
+}
@@ -0,0 +1,335 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Shared types for the D-Bus layer: the per-tree `DbusContext`, the queue
+//! of adds/removes/property-changes the engine defers until the reactor can
+//! apply them to the live `Tree` and `Connection`, the queue of worker-
+//! thread results a method handler is still waiting on, and the numeric
+//! codes returned alongside a method's human-readable result string.
+
+extern crate nix;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use dbus;
+use dbus::Message;
+use dbus::arg::Variant;
+use dbus::tree::{MTFn, ObjectPath};
+
+use self::nix::fcntl::{fcntl, FcntlArg, OFlag};
+use self::nix::unistd::{pipe, write};
+
+use engine::Engine;
+use engine::{DevUuid, FilesystemUuid, PoolUuid};
+
+use super::blockdev::BlockDevProperties;
+use super::filesystem::FilesystemProperties;
+use super::pool::PoolProperties;
+
+/// Data associated with each object path registered in the tree: just
+/// enough to map a path back to the engine object it represents.
+#[derive(Debug, Clone, Copy)]
+pub struct OPath {
+    pub uuid: PoolUuid,
+}
+
+pub type TData = (OPath,);
+
+/// The `Send`-safe outcome of a blocking fd operation a worker thread ran
+/// on behalf of a method handler. Carries only owned, `Send` data -- no
+/// `Message` (dbus-rs's `Message` is `!Send`) and no `DbusContext` (it holds
+/// `Rc`s) -- so it's the only thing that may cross the `thread::spawn`
+/// boundary back to the reactor. The reactor looks up the `Message` this
+/// belongs to by the serial stashed alongside it in `DbusContext::pending`
+/// and finishes the reply on the main thread, where touching `engine` and
+/// building `Message`s is safe again.
+pub enum CompletionResult {
+    /// `ExportPoolMetadata`'s write to the caller's fd finished; carries the
+    /// number of bytes written, or the I/O error's message.
+    Write(Result<u64, String>),
+    /// `ImportPoolMetadata`'s read from the caller's fd finished; carries
+    /// the blob read, or the I/O error's message.
+    Read(Result<Vec<u8>, String>),
+}
+
+/// A reply a method handler is still waiting on while its worker thread
+/// runs, keyed by a serial so the `CompletionResult` that eventually comes
+/// back over the self-pipe can be matched to the right `Message`. Lives
+/// only on the main thread -- `Message` is `!Send`.
+pub enum PendingReply {
+    Export(Message),
+    /// The reply, plus the `force` flag `ImportPoolMetadata` still needs to
+    /// run the engine call its worker thread couldn't.
+    Import(Message, bool),
+}
+
+/// Shared, cloneable handle every method/property callback gets via
+/// `m.tree.get_data()`. Cheap to clone: everything behind `Rc`/`RefCell`.
+#[derive(Clone)]
+pub struct DbusContext {
+    pub engine: Rc<RefCell<Engine>>,
+    pub actions: Rc<RefCell<ActionQueue>>,
+    /// The live `PropertyCache`s for every registered pool/filesystem/
+    /// blockdev object, keyed by uuid, so code outside the `on_get`
+    /// closures that built them -- udev handling, `process_deferred_actions`,
+    /// the methods that mutate the engine -- can refresh them the moment
+    /// the engine's state actually moves, instead of waiting for a client
+    /// to poll by reading the property.
+    pool_properties: Rc<RefCell<HashMap<PoolUuid, Rc<PoolProperties>>>>,
+    filesystem_properties: Rc<RefCell<HashMap<FilesystemUuid, Rc<FilesystemProperties>>>>,
+    blockdev_properties: Rc<RefCell<HashMap<DevUuid, Rc<BlockDevProperties>>>>,
+    next_serial: Rc<Cell<u64>>,
+    /// Method calls whose reply is blocked on a worker thread, keyed by the
+    /// serial handed to that thread's `CompletionSink`.
+    pending: Rc<RefCell<VecDeque<(u64, PendingReply)>>>,
+    /// `Send`-safe results worker threads have finished computing, each
+    /// tagged with the serial of the `PendingReply` it completes. Only this
+    /// field -- never `DbusContext` itself -- is handed to a worker thread.
+    completions: Arc<Mutex<VecDeque<(u64, CompletionResult)>>>,
+    /// Write end of a self-pipe: a worker thread writes a byte here after
+    /// pushing onto `completions` so the reactor's poll wakes up and drains
+    /// it immediately, instead of waiting for some unrelated fd to fire.
+    completion_wakeup: RawFd,
+    /// Read end of the same pipe; the reactor registers this with its poll
+    /// instance. Non-blocking, so a drain loop that races a writer can
+    /// finish on `EWOULDBLOCK` instead of blocking the reactor on an empty
+    /// pipe.
+    pub(super) completion_read: RawFd,
+}
+
+impl DbusContext {
+    pub fn new(engine: Rc<RefCell<Engine>>) -> DbusContext {
+        let (completion_read, completion_wakeup) =
+            pipe().expect("failed to create completion wakeup pipe");
+        fcntl(completion_read, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .expect("failed to set completion pipe read end non-blocking");
+        DbusContext {
+            engine,
+            actions: Rc::new(RefCell::new(ActionQueue::new())),
+            pool_properties: Rc::new(RefCell::new(HashMap::new())),
+            filesystem_properties: Rc::new(RefCell::new(HashMap::new())),
+            blockdev_properties: Rc::new(RefCell::new(HashMap::new())),
+            next_serial: Rc::new(Cell::new(0)),
+            pending: Rc::new(RefCell::new(VecDeque::new())),
+            completions: Arc::new(Mutex::new(VecDeque::new())),
+            completion_wakeup,
+            completion_read,
+        }
+    }
+
+    pub fn register_pool_properties(&self, uuid: PoolUuid, properties: Rc<PoolProperties>) {
+        self.pool_properties.borrow_mut().insert(uuid, properties);
+    }
+
+    pub fn pool_properties(&self, uuid: PoolUuid) -> Option<Rc<PoolProperties>> {
+        self.pool_properties.borrow().get(&uuid).cloned()
+    }
+
+    pub fn unregister_pool_properties(&self, uuid: PoolUuid) {
+        self.pool_properties.borrow_mut().remove(&uuid);
+    }
+
+    pub fn register_filesystem_properties(&self,
+                                          uuid: FilesystemUuid,
+                                          properties: Rc<FilesystemProperties>) {
+        self.filesystem_properties.borrow_mut().insert(uuid, properties);
+    }
+
+    pub fn filesystem_properties(&self, uuid: FilesystemUuid) -> Option<Rc<FilesystemProperties>> {
+        self.filesystem_properties.borrow().get(&uuid).cloned()
+    }
+
+    pub fn unregister_filesystem_properties(&self, uuid: FilesystemUuid) {
+        self.filesystem_properties.borrow_mut().remove(&uuid);
+    }
+
+    pub fn register_blockdev_properties(&self, uuid: DevUuid, properties: Rc<BlockDevProperties>) {
+        self.blockdev_properties.borrow_mut().insert(uuid, properties);
+    }
+
+    pub fn blockdev_properties(&self, uuid: DevUuid) -> Option<Rc<BlockDevProperties>> {
+        self.blockdev_properties.borrow().get(&uuid).cloned()
+    }
+
+    pub fn unregister_blockdev_properties(&self, uuid: DevUuid) {
+        self.blockdev_properties.borrow_mut().remove(&uuid);
+    }
+
+    /// Register `reply` as waiting on a worker thread and return the serial
+    /// its `CompletionSink::push` must tag its result with. Main-thread
+    /// only, same as every other `Message`-touching call.
+    pub fn register_pending(&self, reply: PendingReply) -> u64 {
+        let serial = self.next_serial.get();
+        self.next_serial.set(serial + 1);
+        self.pending.borrow_mut().push_back((serial, reply));
+        serial
+    }
+
+    /// Pull the `PendingReply` matching `serial` back out, if it's still
+    /// there. Main-thread only.
+    fn take_pending(&self, serial: u64) -> Option<PendingReply> {
+        let mut pending = self.pending.borrow_mut();
+        let index = pending.iter().position(|&(s, _)| s == serial)?;
+        pending.remove(index).map(|(_, reply)| reply)
+    }
+
+    /// A `Send`-safe clone of just the completion queue and its wakeup fd,
+    /// for handing to a worker thread. Deliberately does not expose
+    /// `engine`/`actions`/`pending` -- those stay `Rc`-bound to this thread.
+    pub fn completion_sink(&self) -> CompletionSink {
+        CompletionSink {
+            completions: Arc::clone(&self.completions),
+            wakeup: self.completion_wakeup,
+        }
+    }
+
+    /// Drain every queued `CompletionResult`, pairing each with its
+    /// `PendingReply` by serial. Only meant to be called from the reactor
+    /// thread, which alone may touch `Message`/`engine` again.
+    pub fn drain_completions(&self) -> Vec<(PendingReply, CompletionResult)> {
+        let pending: Vec<(u64, CompletionResult)> = self.completions
+            .lock()
+            .expect("completions mutex poisoned")
+            .drain(..)
+            .collect();
+        pending
+            .into_iter()
+            .filter_map(|(serial, result)| self.take_pending(serial).map(|reply| (reply, result)))
+            .collect()
+    }
+}
+
+/// The only thing a worker thread doing blocking fd I/O is allowed to hold:
+/// a handle back to the completion queue and its wakeup fd, both `Send`.
+/// Cloning is cheap (an `Arc` and a `RawFd`), so every spawned thread gets
+/// its own.
+#[derive(Clone)]
+pub struct CompletionSink {
+    completions: Arc<Mutex<VecDeque<(u64, CompletionResult)>>>,
+    wakeup: RawFd,
+}
+
+impl CompletionSink {
+    /// Queue `result` under `serial` and wake the reactor so it's picked up
+    /// promptly. Safe to call from any thread.
+    pub fn push(&self, serial: u64, result: CompletionResult) {
+        self.completions
+            .lock()
+            .expect("completions mutex poisoned")
+            .push_back((serial, result));
+        let _ = write(self.wakeup, &[0u8]);
+    }
+}
+
+/// A change to the D-Bus tree or connection that the engine asked for while
+/// a method was running, deferred until the reactor is between dispatches
+/// so the tree isn't mutated out from under the method call that's still
+/// iterating over it.
+pub enum DeferredAction {
+    /// Register a new object path (a pool, filesystem, or blockdev).
+    Add(ObjectPath<MTFn<TData>, TData>),
+    /// Unregister an existing object path.
+    Remove(dbus::Path<'static>),
+    /// A cached property on an existing object changed value; emit
+    /// `org.freedesktop.DBus.Properties.PropertiesChanged` for it.
+    PropertyChanged {
+        path: dbus::Path<'static>,
+        interface: String,
+        property: String,
+        value: Variant<Box<dbus::arg::RefArg>>,
+    },
+}
+
+/// FIFO of `DeferredAction`s, drained by `process_deferred_actions` once per
+/// reactor iteration.
+pub struct ActionQueue {
+    queue: VecDeque<DeferredAction>,
+}
+
+impl ActionQueue {
+    pub fn new() -> ActionQueue {
+        ActionQueue { queue: VecDeque::new() }
+    }
+
+    pub fn push_add(&mut self, path: ObjectPath<MTFn<TData>, TData>) {
+        self.queue.push_back(DeferredAction::Add(path));
+    }
+
+    pub fn push_remove(&mut self, path: dbus::Path<'static>) {
+        self.queue.push_back(DeferredAction::Remove(path));
+    }
+
+    /// Queue a `PropertiesChanged` emission for a single property. Called by
+    /// the pool/filesystem/blockdev modules whenever they notice the engine
+    /// has changed a cached value (e.g. `Pool.TotalPhysicalUsed`).
+    pub fn push_property_changed(&mut self,
+                                 path: dbus::Path<'static>,
+                                 interface: &str,
+                                 property: &str,
+                                 value: Variant<Box<dbus::arg::RefArg>>) {
+        self.queue
+            .push_back(DeferredAction::PropertyChanged {
+                           path,
+                           interface: interface.to_owned(),
+                           property: property.to_owned(),
+                           value,
+                       });
+    }
+
+    pub fn drain(&mut self) -> ::std::collections::vec_deque::Drain<DeferredAction> {
+        self.queue.drain(..)
+    }
+}
+
+/// Caches a single property value and queues a `PropertiesChanged` emission
+/// on every update that changes it, instead of each mutable property re-implementing the
+/// same "did it actually change, and if so enqueue a signal" dance. The
+/// pool/filesystem/blockdev modules hold one of these per mutable property
+/// (e.g. `Pool.TotalPhysicalUsed`) and call `set()` whenever they notice the
+/// engine's value moved.
+pub struct PropertyCache<T: Clone + Into<dbus::arg::Variant<Box<dbus::arg::RefArg>>>> {
+    value: RefCell<T>,
+}
+
+impl<T> PropertyCache<T>
+    where T: Clone + PartialEq + Into<dbus::arg::Variant<Box<dbus::arg::RefArg>>>
+{
+    pub fn new(value: T) -> PropertyCache<T> {
+        PropertyCache { value: RefCell::new(value) }
+    }
+
+    pub fn get(&self) -> T {
+        self.value.borrow().clone()
+    }
+
+    /// Update the cached value; if it actually changed, queue a
+    /// `PropertiesChanged` emission for `property` on `path`.
+    pub fn set(&self,
+               actions: &Rc<RefCell<ActionQueue>>,
+               path: dbus::Path<'static>,
+               interface: &str,
+               property: &str,
+               new_value: T) {
+        let changed = *self.value.borrow() != new_value;
+        if changed {
+            *self.value.borrow_mut() = new_value.clone();
+            actions
+                .borrow_mut()
+                .push_property_changed(path, interface, property, new_value.into());
+        }
+    }
+}
+
+/// Return codes stratisd hands back to D-Bus clients alongside a
+/// human-readable string, independent of the richer `ErrorEnum` the engine
+/// itself uses internally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbusErrorEnum {
+    OK = 0,
+    ERROR = 1,
+}
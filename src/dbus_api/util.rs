@@ -0,0 +1,95 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Small helpers shared by every method/property handler in `api.rs`, plus
+//! the macros they use to bail out of a handler early with an error reply.
+
+use dbus;
+use dbus::arg::{Arg, Get, Iter};
+use dbus::tree::MethodErr;
+
+use uuid::Uuid;
+
+use engine::errors::EngineError;
+
+use super::types::DbusErrorEnum;
+
+pub const STRATIS_BASE_SERVICE: &'static str = "org.storage.stratis1";
+pub const STRATIS_BASE_PATH: &'static str = "/org/storage/stratis1";
+
+/// The D-Bus object path `create_dbus_pool` gives the pool `uuid`, computed
+/// without going through the engine or the tree -- useful to code (e.g. the
+/// udev handler) that only has a uuid in hand and needs to refresh that
+/// object's properties.
+pub fn pool_object_path(uuid: Uuid) -> dbus::Path<'static> {
+    child_object_path(&dbus::Path::new(STRATIS_BASE_PATH).expect("a valid path"), uuid)
+}
+
+/// The D-Bus object path `create_dbus_filesystem`/`create_dbus_blockdev`
+/// give a child object of `parent` named `uuid`.
+pub fn child_object_path(parent: &dbus::Path<'static>, uuid: Uuid) -> dbus::Path<'static> {
+    dbus::Path::new(format!("{}/{}", parent, uuid.simple()))
+        .expect("parent is a valid path and uuid.simple() contains no invalid path characters")
+}
+
+/// Pull the next argument off a method call's argument iterator, translating
+/// a missing/mistyped argument into the `MethodErr` a `MethodResult` handler
+/// can bail out on with `?`.
+pub fn get_next_arg<'a, T>(iter: &mut Iter<'a>, arg_num: u64) -> Result<T, MethodErr>
+    where T: Get<'a> + Arg
+{
+    iter.next()
+        .ok_or_else(|| MethodErr::no_arg())
+        .and_then(|_| {
+                      iter.read::<T>()
+                          .map_err(|_| MethodErr::invalid_arg(&arg_num))
+                  })
+}
+
+/// Turn the `(bool, u16)` pair dbus-rs hands back for an `Option<u16>`
+/// in-arg into a real `Option`.
+pub fn tuple_to_option(value: (bool, u16)) -> Option<u16> {
+    if value.0 { Some(value.1) } else { None }
+}
+
+pub fn msg_code_ok() -> u16 {
+    DbusErrorEnum::OK as u16
+}
+
+pub fn msg_string_ok() -> String {
+    String::new()
+}
+
+/// Translate an `EngineError` into the `(return_code, return_string)` pair
+/// every stratisd D-Bus method appends after its normal results.
+pub fn engine_to_dbus_err_tuple(err: &EngineError) -> (u16, String) {
+    (DbusErrorEnum::ERROR as u16, format!("{}", err))
+}
+
+/// Pull the `OPath` a tree node was registered with out of its data tuple.
+/// `$default`/`$return_message` are accepted (but unused here) so call
+/// sites read the same way as `get_mut_pool!`, which does need them.
+macro_rules! get_data {
+    ($path:expr; $default:expr; $return_message:expr) => {
+        &($path.get_data().0)
+    }
+}
+
+/// Look up `$uuid` in `$engine`, returning the matching pool or bailing out
+/// of the enclosing `MethodResult` handler with a `NotFound` error reply if
+/// it's gone missing (e.g. destroyed by a racing request).
+macro_rules! get_mut_pool {
+    ($engine:expr; $uuid:expr; $default:expr; $return_message:expr) => {
+        match $engine.get_mut_pool($uuid) {
+            Some(pool) => pool,
+            None => {
+                let (rc, rs) = engine_to_dbus_err_tuple(
+                    &engine::errors::EngineError::Engine(
+                        engine::errors::ErrorEnum::NotFound,
+                        format!("pool {} no longer exists", $uuid)));
+                return Ok(vec![$return_message.append3($default, rc, rs)]);
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The error type every engine operation returns, and the coarse category
+//! (`ErrorEnum`) D-Bus and JSON transports map onto their own return codes.
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorEnum {
+    Error,
+    AlreadyExists,
+    Busy,
+    Invalid,
+    NotFound,
+}
+
+impl fmt::Display for ErrorEnum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            ErrorEnum::Error => "Error",
+            ErrorEnum::AlreadyExists => "AlreadyExists",
+            ErrorEnum::Busy => "Busy",
+            ErrorEnum::Invalid => "Invalid",
+            ErrorEnum::NotFound => "NotFound",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug)]
+pub enum EngineError {
+    Engine(ErrorEnum, String),
+    Io(io::Error),
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EngineError::Engine(ref kind, ref msg) => write!(f, "{}: {}", kind, msg),
+            EngineError::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for EngineError {
+    fn from(err: io::Error) -> EngineError {
+        EngineError::Io(err)
+    }
+}
+
+pub type EngineResult<T> = Result<T, EngineError>;
@@ -0,0 +1,104 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The engine layer: the `Engine`/`Pool`/`Filesystem`/`BlockDev` traits the
+//! D-Bus and JSON transports drive, independent of whichever concrete
+//! engine (real or simulated) backs them.
+
+pub mod errors;
+pub mod strat_engine;
+
+use std::path::Path;
+
+use uuid::Uuid;
+
+use self::errors::EngineResult;
+
+pub type PoolUuid = Uuid;
+pub type FilesystemUuid = Uuid;
+pub type DevUuid = Uuid;
+
+/// The entry point every transport (D-Bus, the Unix-socket gateway) drives.
+/// A concrete implementation owns every pool it manages and is always
+/// reached through `Rc<RefCell<Engine>>`, so every method that mutates
+/// engine state takes `&mut self`; methods that only read reach into pools
+/// through `&self` so callers already holding an immutable borrow (e.g. to
+/// iterate `pools()`) can still call into them.
+pub trait Engine {
+    /// Create a new pool named `name` on `blockdev_paths`, returning its
+    /// uuid. `force` allows reusing devices that look like they might
+    /// belong to another pool.
+    fn create_pool(&mut self,
+                    name: &str,
+                    blockdev_paths: &[&Path],
+                    redundancy: Option<u16>,
+                    force: bool)
+                    -> EngineResult<PoolUuid>;
+
+    /// Tear down and forget the pool identified by `uuid`. Returns whether
+    /// a pool was actually found and destroyed.
+    fn destroy_pool(&mut self, uuid: PoolUuid) -> EngineResult<bool>;
+
+    /// Adjust the probability the simulator engine injects a failure;
+    /// real engines should no-op.
+    fn configure_simulator(&mut self, denominator: u32) -> EngineResult<()>;
+
+    fn get_pool(&self, uuid: PoolUuid) -> Option<&Pool>;
+
+    fn get_mut_pool(&mut self, uuid: PoolUuid) -> Option<&mut Pool>;
+
+    /// Every pool the engine currently manages.
+    fn pools(&self) -> Vec<&Pool>;
+
+    /// Serialize `uuid`'s on-disk metadata/MDA regions so they can be
+    /// streamed out to a caller-supplied destination.
+    fn export_pool_metadata(&self, uuid: PoolUuid) -> EngineResult<Vec<u8>>;
+
+    /// Reconstruct a pool record from a previously exported metadata blob.
+    /// `force` allows overwriting an existing record for that pool's uuid.
+    fn import_pool_metadata(&mut self, blob: &[u8], force: bool) -> EngineResult<()>;
+}
+
+/// A single storage pool.
+pub trait Pool {
+    fn uuid(&self) -> PoolUuid;
+
+    fn blockdevs(&self) -> Vec<&BlockDev>;
+
+    fn filesystems(&self) -> Vec<&Filesystem>;
+
+    /// Told about a udev block event (`event_type`, e.g. "add"/"remove"/
+    /// "change") for `devnode`/`wwn` when one of them matches a blockdev
+    /// this pool owns. Interior mutability, not `&mut self`: called from
+    /// the reactor while it's only holding an immutable borrow of the
+    /// engine's list of pools.
+    fn block_evaluate(&self, event_type: &str, devnode: &Path, wwn: Option<&str>);
+
+    /// Total physical space across every blockdev in the pool, in bytes.
+    fn total_physical_size(&self) -> u64;
+
+    /// Physical space currently allocated to filesystems, in bytes.
+    fn total_physical_used(&self) -> u64;
+}
+
+/// A filesystem created on top of a pool.
+pub trait Filesystem {
+    fn uuid(&self) -> FilesystemUuid;
+
+    /// Space currently used within the filesystem, in bytes.
+    fn used(&self) -> u64;
+}
+
+/// A block device contributing storage to a pool.
+pub trait BlockDev {
+    fn uuid(&self) -> DevUuid;
+
+    fn devnode(&self) -> &Path;
+
+    /// The hardware's `ID_WWN`, if udev reported one.
+    fn wwn(&self) -> Option<&str>;
+
+    /// Human-readable state, e.g. "in-use", "missing", "failed".
+    fn state(&self) -> String;
+}
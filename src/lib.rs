@@ -0,0 +1,19 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! stratisd: manages storage pools built out of block devices and the
+//! filesystems on top of them, exposed over D-Bus and a line-oriented JSON
+//! socket.
+
+extern crate dbus;
+extern crate uuid;
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+pub mod dbus_api;
+pub mod engine;